@@ -0,0 +1,74 @@
+//! Serializes assembled instruction words into the output formats downstream tools expect
+//!
+//! Each word is paired with the address it was assembled at (honoring any `.org` relocation),
+//! so formats that embed an address column reflect the program's actual load address.
+
+/// Plain newline-separated 8-digit hex words, one per instruction, in program order
+pub fn hex(words: &[(u16, u32)]) -> String {
+    words
+        .iter()
+        .map(|(_, word)| format!("{word:08x}\n"))
+        .collect()
+}
+
+/// Quartus MIF, with the given `DEPTH`/`WIDTH` header, addresses starting at each word's `.org` base
+pub fn mif(words: &[(u16, u32)], depth: u32, width: u32) -> String {
+    let mut out = format!(
+        "DEPTH = {depth};\nWIDTH = {width};\nADDRESS_RADIX = HEX;\nDATA_RADIX = HEX;\nCONTENT\nBEGIN\n\n"
+    );
+    for (addr, word) in words {
+        out.push_str(&format!("{addr:04x}: {word:08x};\n"));
+    }
+    out.push_str("\nEND;\n");
+    out
+}
+
+/// One `:LLAAAATT...CC` data record per word plus a trailing `:00000001FF` EOF record, with each
+/// record's address starting from the word's `.org` base instead of always zero. Byte addresses
+/// past 16 bits are carried by a type 04 extended linear address record emitted whenever their
+/// upper 16 bits change
+pub fn intel_hex(words: &[(u16, u32)]) -> String {
+    let mut out = String::new();
+    let mut upper: u16 = 0;
+    for (addr, word) in words {
+        let byte_addr = u32::from(*addr) * 4;
+        let segment = (byte_addr >> 16) as u16;
+        if segment != upper {
+            out.push_str(&intel_hex_record(0, 0x04, &segment.to_be_bytes()));
+            out.push('\n');
+            upper = segment;
+        }
+        out.push_str(&intel_hex_record(byte_addr as u16, 0x00, &word.to_be_bytes()));
+        out.push('\n');
+    }
+    out.push_str(":00000001FF\n");
+    out
+}
+
+/// Builds one Intel HEX record, appending its two's-complement checksum byte
+fn intel_hex_record(addr: u16, record_type: u8, data: &[u8]) -> String {
+    let mut record = vec![data.len() as u8];
+    record.extend_from_slice(&addr.to_be_bytes());
+    record.push(record_type);
+    record.extend_from_slice(data);
+
+    let sum = record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    record.push(sum.wrapping_neg());
+
+    let body: String = record.iter().map(|byte| format!("{byte:02X}")).collect();
+    format!(":{body}")
+}
+
+/// Raw binary, each word serialized in the requested byte order, in program order
+pub fn raw_binary(words: &[(u16, u32)], big_endian: bool) -> Vec<u8> {
+    words
+        .iter()
+        .flat_map(|(_, word)| {
+            if big_endian {
+                word.to_be_bytes()
+            } else {
+                word.to_le_bytes()
+            }
+        })
+        .collect()
+}
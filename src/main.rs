@@ -5,6 +5,8 @@ use deku::prelude::*;
 use std::collections::HashMap;
 use std::fs;
 
+mod emit;
+
 /// ReCOP Instruction definition
 #[derive(PartialEq, Debug, DekuRead, DekuWrite)]
 #[deku(endian = "big")]
@@ -21,7 +23,7 @@ struct Inst {
 }
 
 /// Opcode as specified in the Instruction file
-#[derive(Debug, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Deserialize)]
 struct Opcode {
     opcode: u8,
     args: u8,
@@ -41,6 +43,19 @@ enum AddrMode {
     Reg = 3,
 }
 
+impl AddrMode {
+    /// Recovers an `AddrMode` from the 2-bit field decoded off the wire
+    fn from_bits(bits: u8) -> Option<Self> {
+        match bits {
+            0 => Some(AddrMode::Inh),
+            1 => Some(AddrMode::Imm),
+            2 => Some(AddrMode::Dir),
+            3 => Some(AddrMode::Reg),
+            _ => None,
+        }
+    }
+}
+
 /// Overly complicated generic function to import toml files
 pub fn import_toml<T: serde::de::DeserializeOwned>(
     path: &str,
@@ -68,6 +83,10 @@ enum Error {
     DirInvalid,
     InhInvalid,
     ArgParse,
+    MacroRecursion,
+    ConstUndefined,
+    OperandOverflow,
+    LabelUndefined(String),
 }
 
 /// Error with line and contents of line
@@ -97,6 +116,30 @@ impl std::fmt::Display for Error {
             Error::DirInvalid => write!(f, "opcode does not have direct addressing mode"),
             Error::InhInvalid => write!(f, "opcode does not have inherent addressing mode"),
             Error::ArgParse => write!(f, "failed to parse argument"),
+            Error::MacroRecursion => write!(f, "macro expansion exceeded the recursion limit"),
+            Error::ConstUndefined => write!(f, "undefined constant or label in expression"),
+            Error::OperandOverflow => {
+                write!(f, "expression does not fit in the 16-bit operand or address field")
+            }
+            Error::LabelUndefined(name) => write!(f, "label `{name}` is undefined"),
+        }
+    }
+}
+
+/// Possible disassembler errors
+#[derive(Debug)]
+enum DisasmError {
+    DecodeFailed,
+    OpcodeUnknown(u8),
+}
+
+impl std::fmt::Display for DisasmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DisasmError::DecodeFailed => write!(f, "failed to decode an instruction word"),
+            DisasmError::OpcodeUnknown(opcode) => {
+                write!(f, "no mnemonic is defined for opcode {opcode}")
+            }
         }
     }
 }
@@ -104,6 +147,7 @@ impl std::fmt::Display for Error {
 #[derive(Debug, PartialEq, Copy, Clone)]
 enum TokenType {
     Label,
+    Org,
     Opcode,
     Reg,
     Imm,
@@ -129,6 +173,38 @@ impl Token {
     }
 }
 
+/// A `.macro` ... `.endm` definition: its ordered parameter names and body lines
+#[derive(Debug, Clone)]
+struct MacroDef {
+    params: Vec<String>,
+    body: Vec<Vec<Token>>,
+}
+
+/// Maximum nesting depth allowed when a macro body invokes other macros
+const MAX_MACRO_DEPTH: u32 = 256;
+
+/// Output formats the assembler can emit a program as
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Format {
+    Hex,
+    Mif,
+    IntelHex,
+    BinLe,
+    BinBe,
+}
+
+impl Format {
+    /// Default output file name for this format, used when `--output` is not given
+    fn default_output_path(self) -> &'static str {
+        match self {
+            Format::Hex => "out.txt",
+            Format::Mif => "out.mif",
+            Format::IntelHex => "out.hex",
+            Format::BinLe | Format::BinBe => "out.bin",
+        }
+    }
+}
+
 /// CLI args
 use clap::Parser;
 #[derive(Parser, Debug)]
@@ -139,10 +215,129 @@ struct Args {
     #[arg(short, long, value_name = "FILE")]
     instructions: String,
     input: String,
-    #[arg(short, long, value_name = "FILE")]
-    mif_output: Option<String>,
     #[arg(short, long, action = clap::ArgAction::SetTrue)]
     debug: bool,
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    disassemble: bool,
+    // selects a single output format; if omitted, both plain hex and MIF are written (as before
+    // `--format` existed)
+    #[arg(long, value_enum)]
+    format: Option<Format>,
+    #[arg(long, value_name = "FILE", default_value = "out.mif")]
+    mif_output: String,
+    #[arg(long, default_value_t = 32768)]
+    mif_depth: u32,
+    #[arg(long, default_value_t = 32)]
+    mif_width: u32,
+}
+
+/// Lists the addressing modes an opcode's TOML entry marks as supported, for diagnostics
+fn supported_modes(opcode: &Opcode) -> Vec<&'static str> {
+    let mut modes = Vec::new();
+    if opcode.inh.unwrap_or(false) {
+        modes.push("inherent");
+    }
+    if opcode.reg.unwrap_or(false) {
+        modes.push("register (rN)");
+    }
+    if opcode.imm.unwrap_or(false) {
+        modes.push("immediate (#N)");
+    }
+    if opcode.dir.unwrap_or(false) {
+        modes.push("direct ($N)");
+    }
+    modes
+}
+
+/// A contextual help note to print under an error, tailored to the `Error` variant
+fn help_for(err: &Error, line_text: &str, opcodes: &HashMap<String, Opcode>) -> Option<String> {
+    let opcode_name = || {
+        line_text
+            .trim()
+            .split_whitespace()
+            .next()
+            .map(str::to_lowercase)
+    };
+
+    match err {
+        Error::OpcodeExpected => Some("expected an opcode at the start of the line".to_string()),
+        Error::OpcodeUndefined => {
+            Some("check the opcode name against the instruction set file".to_string())
+        }
+        Error::RegExpected => Some("expected a register operand, e.g. `r0`".to_string()),
+        Error::OperandExpected => {
+            Some("expected a register, immediate (#N), or direct ($N) operand".to_string())
+        }
+        Error::ArgsNumber => {
+            let opcode = opcodes.get(&opcode_name()?)?;
+            Some(format!("this opcode expects {} argument(s)", opcode.args))
+        }
+        Error::ImmInvalid | Error::RegInvalid | Error::DirInvalid | Error::InhInvalid => {
+            let opcode = opcodes.get(&opcode_name()?)?;
+            let modes = supported_modes(opcode);
+            if modes.is_empty() {
+                Some("this opcode supports no addressing modes".to_string())
+            } else {
+                Some(format!("this opcode supports: {}", modes.join(", ")))
+            }
+        }
+        Error::ArgParse => {
+            Some("expected a decimal number, hex literal, or expression".to_string())
+        }
+        Error::MacroRecursion => Some(format!(
+            "expansion nested past {MAX_MACRO_DEPTH} levels; check for a macro invoking itself"
+        )),
+        Error::ConstUndefined => {
+            Some("no `const`/`.equ` or label with this name has been defined yet".to_string())
+        }
+        Error::OperandOverflow => Some(
+            "value must fit between -32768 and 65535 (0 to 65535 for an `.org` address)"
+                .to_string(),
+        ),
+        Error::LabelUndefined(name) => Some(format!(
+            "define `{name}:` somewhere in the program, or check for a typo"
+        )),
+    }
+}
+
+/// Renders an `AsmError` as the line, a caret run under the offending token, and a help note
+fn report_error(error: &AsmError, lines: &[&str], opcodes: &HashMap<String, Opcode>) {
+    let line_text = lines[error.token.line_num as usize];
+    let gutter = format!("{:>4}", error.token.line_num);
+    let margin = " ".repeat(gutter.len());
+    let (start, end) = error.token.chars;
+    let lead = " ".repeat(start as usize);
+    let underline = "^".repeat((end.saturating_sub(start)).max(1) as usize);
+
+    let _ = execute!(
+        std::io::stdout(),
+        SetForegroundColor(Color::Red),
+        SetAttribute(Attribute::Bold),
+        Print("error"),
+        SetForegroundColor(Color::Reset),
+        Print(": "),
+        Print(format!("{}\n", error.err)),
+        SetAttribute(Attribute::Reset),
+        Print(format!(" {gutter} │ {line_text}\n")),
+        Print(format!(" {margin} │ {lead}")),
+        SetForegroundColor(Color::Red),
+        Print(format!("{underline}\n")),
+        SetForegroundColor(Color::Reset),
+    );
+
+    if let Some(help) = help_for(&error.err, line_text, opcodes) {
+        let _ = execute!(
+            std::io::stdout(),
+            Print(format!(" {margin} │\n")),
+            SetForegroundColor(Color::Cyan),
+            SetAttribute(Attribute::Bold),
+            Print("help"),
+            SetForegroundColor(Color::Reset),
+            Print(": "),
+            SetAttribute(Attribute::Reset),
+            Print(format!("{help}\n")),
+        );
+    }
 }
 
 fn main() -> Result<(), ()> {
@@ -168,55 +363,82 @@ fn main() -> Result<(), ()> {
         }
     };
 
-    let instructions = match assemble(&input, opcode_map, args.debug) {
+    if args.disassemble {
+        let source = match disassemble(&parse_words(&input), &opcode_map) {
+            Err(error) => {
+                println!("{error}");
+                return Err(());
+            }
+            Ok(value) => value,
+        };
+
+        match &args.output {
+            Some(path) => match fs::write(path, &source) {
+                Err(_) => println!("Could not write to file {}", path),
+                Ok(_) => {}
+            },
+            None => print!("{source}"),
+        }
+
+        return Ok(());
+    }
+
+    let instructions = match assemble(&input, opcode_map.clone(), args.debug) {
         Err(error) => {
             let lines: Vec<_> = input.trim().split("\n").map(|x| x.trim()).collect();
-            let _ = execute!(
-                std::io::stdout(),
-                SetForegroundColor(Color::Red),
-                SetAttribute(Attribute::Bold),
-                Print("error"),
-                SetForegroundColor(Color::Reset),
-                Print(": "),
-                Print(format!("{}\n", error.err)),
-                SetAttribute(Attribute::Reset),
-                Print(format!(
-                    " {:>2} │ {}\n",
-                    error.token.line_num, lines[error.token.line_num as usize]
-                ))
-            );
+            report_error(&error, &lines, &opcode_map);
             return Err(());
         }
         Ok(value) => value,
     };
 
-    let inst: Vec<u32> = instructions
+    let words: Vec<(u16, u32)> = instructions
         .iter()
-        .map(|x| x.to_bits().unwrap().load_be())
+        .map(|(address, inst)| (*address, inst.to_bits().unwrap().load_be()))
         .collect();
-    let inst_hex: String = inst.iter().map(|x| format!("{x:08x}\n")).collect();
 
-    let output_path = args.output.unwrap_or("out.txt".to_string());
-    match fs::write(&output_path, inst_hex) {
-        Err(_) => println!("Could not write to file {}", output_path),
-        Ok(_) => {}
+    // an explicit `--format` opts into writing exactly that one format; with none given,
+    // preserve the original behavior of always writing both plain hex and MIF
+    let outputs: Vec<(String, std::io::Result<()>)> = match args.format {
+        Some(format) => {
+            let output_path = args
+                .output
+                .clone()
+                .unwrap_or_else(|| format.default_output_path().to_string());
+            let write_result = match format {
+                Format::Hex => fs::write(&output_path, emit::hex(&words)),
+                Format::Mif => fs::write(
+                    &output_path,
+                    emit::mif(&words, args.mif_depth, args.mif_width),
+                ),
+                Format::IntelHex => fs::write(&output_path, emit::intel_hex(&words)),
+                Format::BinLe => fs::write(&output_path, emit::raw_binary(&words, false)),
+                Format::BinBe => fs::write(&output_path, emit::raw_binary(&words, true)),
+            };
+            vec![(output_path, write_result)]
+        }
+        None => {
+            let hex_path = args
+                .output
+                .clone()
+                .unwrap_or_else(|| Format::Hex.default_output_path().to_string());
+            let mif_path = args.mif_output.clone();
+            vec![
+                (hex_path.clone(), fs::write(&hex_path, emit::hex(&words))),
+                (
+                    mif_path.clone(),
+                    fs::write(&mif_path, emit::mif(&words, args.mif_depth, args.mif_width)),
+                ),
+            ]
+        }
     };
 
-    let mut inst_mif =
-        "DEPTH = 32768;\nWIDTH = 32;\nADDRESS_RADIX = HEX;\nDATA_RADIX = HEX;\nCONTENT\nBEGIN\n\n"
-            .to_string();
-    let inst_mif_hex: String = inst
-        .iter()
-        .enumerate()
-        .map(|(addr, data)| format!("{addr:04x}: {data:08x};\n"))
-        .collect();
-    inst_mif.push_str(&inst_mif_hex);
-    inst_mif.push_str("\nEND;\n");
-    let output_path = args.mif_output.unwrap_or("out.mif".to_string());
-    match fs::write(&output_path, inst_mif) {
-        Err(_) => println!("Could not write to file {}", output_path),
-        Ok(_) => {}
-    };
+    for (path, result) in &outputs {
+        if result.is_err() {
+            println!("Could not write to file {}", path);
+        }
+    }
+    let written_paths: Vec<&str> = outputs.iter().map(|(path, _)| path.as_str()).collect();
 
     let _ = execute!(
         std::io::stdout(),
@@ -226,46 +448,449 @@ fn main() -> Result<(), ()> {
         SetForegroundColor(Color::Reset),
         Print(": "),
         SetAttribute(Attribute::Reset),
-        Print(format!("wrote output to {}\n", output_path))
+        Print(format!("wrote output to {}\n", written_paths.join(", ")))
     );
 
     Ok(())
 }
 
+/// Reads 32-bit big-endian words out of either the plain hex output or an MIF's `addr: data;` lines
+fn parse_words(input: &str) -> Vec<u32> {
+    let mut words = Vec::new();
+    for line in input.lines() {
+        let line = line.trim();
+        let data = match line.split_once(':') {
+            Some((_addr, data)) => data.trim().trim_end_matches(';').trim(),
+            None => line,
+        };
+        if let Ok(word) = u32::from_str_radix(data, 16) {
+            words.push(word);
+        }
+    }
+    words
+}
+
+/// Maps each opcode number back to its mnemonic and TOML metadata
+fn reverse_opcode_map(opcodes: &HashMap<String, Opcode>) -> HashMap<u8, (&String, &Opcode)> {
+    opcodes
+        .iter()
+        .map(|(name, opcode)| (opcode.opcode, (name, opcode)))
+        .collect()
+}
+
+/// Renders a syntax operand for the given addressing mode and decoded value
+fn format_operand(addr_mode: AddrMode, value: u16) -> String {
+    match addr_mode {
+        AddrMode::Inh => String::new(),
+        AddrMode::Imm => format!("#{value}"),
+        AddrMode::Dir => format!("${value}"),
+        AddrMode::Reg => format!("r{value}"),
+    }
+}
+
+/// Reconstructs one line of source from a decoded `Inst` and its mnemonic/metadata
+fn decode_instruction(name: &str, opcode: &Opcode, inst: &Inst) -> String {
+    let args = opcode.args as usize;
+    if args == 0 {
+        return name.to_string();
+    }
+
+    let addr_mode = AddrMode::from_bits(inst.addr_mode).unwrap_or(AddrMode::Inh);
+    let reg_args = [inst.reg_z, inst.reg_x];
+    let plain_args = args - 1;
+
+    // when a register operand fits a spare reg_z/reg_x nibble, the assembler packs it there
+    // instead of the 16-bit operand field; undo that before re-deriving the operand text
+    let operand_value = match (addr_mode, opcode.operand_as_reg) {
+        (AddrMode::Reg, Some(slot)) if args < 3 => reg_args[slot as usize] as u16,
+        _ => inst.operand,
+    };
+
+    let mut parts: Vec<String> = vec![name.to_string()];
+    parts.extend(reg_args[..plain_args].iter().map(|reg| format!("r{reg}")));
+    parts.push(format_operand(addr_mode, operand_value));
+    parts.join(" ")
+}
+
+/// Reverse path: decodes a stream of instruction words back into ReCOP assembly source
+fn disassemble(words: &[u32], opcodes: &HashMap<String, Opcode>) -> Result<String, DisasmError> {
+    let reverse = reverse_opcode_map(opcodes);
+    let mut source = String::new();
+
+    for word in words {
+        let bytes = word.to_be_bytes();
+        let (_, inst) = Inst::from_bytes((&bytes, 0)).map_err(|_| DisasmError::DecodeFailed)?;
+        let (name, opcode) = reverse
+            .get(&inst.opcode)
+            .ok_or(DisasmError::OpcodeUnknown(inst.opcode))?;
+        source.push_str(&decode_instruction(name, opcode, &inst));
+        source.push('\n');
+    }
+
+    Ok(source)
+}
+
 fn assemble(
     input: &String,
     opcodes: HashMap<String, Opcode>,
     debug: bool,
-) -> Result<Vec<Inst>, AsmError> {
+) -> Result<Vec<(u16, Inst)>, AsmError> {
     let input = input.to_lowercase();
-    let (tokens, labels) = lex(input);
+    let (tokens, macros, consts) = lex(input)?;
+    let tokens = expand_macros(tokens, &macros)?;
+    let (labels, tokens) = assign_addresses(tokens, &consts)?;
     if debug {
-        for (line, token) in tokens.iter().enumerate() {
-            println!("{line} | {token:?}");
+        for (address, token) in &tokens {
+            println!("{address:#06x} | {token:?}");
         }
         for label in &labels {
             println!("{} {}", label.0, label.1);
         }
+        for const_def in &consts {
+            println!("{} {}", const_def.0, const_def.1);
+        }
+    }
+    parse(tokens, opcodes, labels, consts)
+}
+
+/// Classifies a bare symbol into its token type and stripped content, e.g. `rX` -> `(Reg, "X")`
+fn classify_symbol(symbol: &[char]) -> (TokenType, String) {
+    match symbol[0] {
+        'r' => (TokenType::Reg, symbol[1..].iter().collect()),
+        '#' => (TokenType::Imm, symbol[1..].iter().collect()),
+        '$' => (TokenType::Dir, symbol[1..].iter().collect()),
+        '\'' => (TokenType::Label, symbol[1..].iter().collect()),
+        _ => (TokenType::Opcode, symbol.iter().collect()),
+    }
+}
+
+/// Splits a line on spaces like `split(" ")` used to, but keeps each symbol's start/end column
+/// so diagnostics can point a caret at exactly the offending token
+fn scan_symbols(line: &str) -> Vec<((u32, u32), Vec<char>)> {
+    let mut symbols = Vec::new();
+    let mut current: Vec<char> = Vec::new();
+    let mut start: Option<usize> = None;
+
+    for (col, ch) in line.chars().enumerate() {
+        if ch == ' ' {
+            if let Some(start_col) = start.take() {
+                symbols.push(((start_col as u32, col as u32), std::mem::take(&mut current)));
+            }
+        } else {
+            start.get_or_insert(col);
+            current.push(ch);
+        }
+    }
+    if let Some(start_col) = start {
+        symbols.push(((start_col as u32, line.chars().count() as u32), current));
+    }
+
+    symbols
+}
+
+/// Re-merges symbols that `scan_symbols` split apart on a space inside parentheses, so a
+/// parenthesized expression operand like `#(TABLE + 4)` tokenizes as one symbol instead of
+/// several stray ones that would blow the arg-count check before `eval_expr` ever sees it
+fn merge_parens(symbols: Vec<((u32, u32), Vec<char>)>) -> Vec<((u32, u32), Vec<char>)> {
+    let paren_depth = |symbol: &[char]| {
+        symbol.iter().filter(|&&c| c == '(').count() as i32
+            - symbol.iter().filter(|&&c| c == ')').count() as i32
+    };
+
+    let mut merged = Vec::new();
+    let mut symbols = symbols.into_iter();
+    while let Some((chars, symbol)) = symbols.next() {
+        let mut depth = paren_depth(&symbol);
+        if depth <= 0 {
+            merged.push((chars, symbol));
+            continue;
+        }
+
+        let (start, mut end) = chars;
+        let mut combined = symbol;
+        while depth > 0 {
+            match symbols.next() {
+                Some((next_chars, next_symbol)) => {
+                    depth += paren_depth(&next_symbol);
+                    combined.push(' ');
+                    combined.extend(next_symbol);
+                    end = next_chars.1;
+                }
+                None => break,
+            }
+        }
+        merged.push(((start, end), combined));
+    }
+
+    merged
+}
+
+/// Evaluates a constant expression over `+ - * / ( )` and unary minus, whose atoms are decimal
+/// or hex literals, previously-defined constants, and (when `labels` is available) label names
+fn eval_expr(
+    expr: &str,
+    consts: &HashMap<String, i64>,
+    labels: Option<&HashMap<String, u16>>,
+) -> Result<i64, Error> {
+    let chars: Vec<char> = expr.chars().filter(|c| !c.is_whitespace()).collect();
+    let mut pos = 0;
+    let value = eval_sum(&chars, &mut pos, consts, labels)?;
+    if pos != chars.len() {
+        return Err(Error::ArgParse);
+    }
+    Ok(value)
+}
+
+fn eval_sum(
+    chars: &[char],
+    pos: &mut usize,
+    consts: &HashMap<String, i64>,
+    labels: Option<&HashMap<String, u16>>,
+) -> Result<i64, Error> {
+    let mut value = eval_product(chars, pos, consts, labels)?;
+    while let Some(op @ ('+' | '-')) = chars.get(*pos) {
+        let op = *op;
+        *pos += 1;
+        let rhs = eval_product(chars, pos, consts, labels)?;
+        value = if op == '+' { value + rhs } else { value - rhs };
+    }
+    Ok(value)
+}
+
+fn eval_product(
+    chars: &[char],
+    pos: &mut usize,
+    consts: &HashMap<String, i64>,
+    labels: Option<&HashMap<String, u16>>,
+) -> Result<i64, Error> {
+    let mut value = eval_unary(chars, pos, consts, labels)?;
+    while let Some(op @ ('*' | '/')) = chars.get(*pos) {
+        let op = *op;
+        *pos += 1;
+        let rhs = eval_unary(chars, pos, consts, labels)?;
+        if op == '/' {
+            if rhs == 0 {
+                return Err(Error::ArgParse);
+            }
+            value /= rhs;
+        } else {
+            value *= rhs;
+        }
+    }
+    Ok(value)
+}
+
+fn eval_unary(
+    chars: &[char],
+    pos: &mut usize,
+    consts: &HashMap<String, i64>,
+    labels: Option<&HashMap<String, u16>>,
+) -> Result<i64, Error> {
+    match chars.get(*pos) {
+        Some('-') => {
+            *pos += 1;
+            Ok(-eval_unary(chars, pos, consts, labels)?)
+        }
+        Some('+') => {
+            *pos += 1;
+            eval_unary(chars, pos, consts, labels)
+        }
+        _ => eval_atom(chars, pos, consts, labels),
+    }
+}
+
+fn eval_atom(
+    chars: &[char],
+    pos: &mut usize,
+    consts: &HashMap<String, i64>,
+    labels: Option<&HashMap<String, u16>>,
+) -> Result<i64, Error> {
+    match chars.get(*pos) {
+        Some('(') => {
+            *pos += 1;
+            let value = eval_sum(chars, pos, consts, labels)?;
+            if chars.get(*pos) != Some(&')') {
+                return Err(Error::ArgParse);
+            }
+            *pos += 1;
+            Ok(value)
+        }
+        Some(c) if c.is_ascii_digit() => {
+            let start = *pos;
+            if *c == '0' && chars.get(*pos + 1) == Some(&'x') {
+                *pos += 2;
+                let digits_start = *pos;
+                while chars.get(*pos).is_some_and(|c| c.is_ascii_hexdigit()) {
+                    *pos += 1;
+                }
+                let text: String = chars[digits_start..*pos].iter().collect();
+                return i64::from_str_radix(&text, 16).map_err(|_| Error::ArgParse);
+            }
+            while chars.get(*pos).is_some_and(|c| c.is_ascii_digit()) {
+                *pos += 1;
+            }
+            let text: String = chars[start..*pos].iter().collect();
+            text.parse().map_err(|_| Error::ArgParse)
+        }
+        Some(c) if c.is_alphabetic() || *c == '_' => {
+            let start = *pos;
+            while chars
+                .get(*pos)
+                .is_some_and(|c| c.is_alphanumeric() || *c == '_')
+            {
+                *pos += 1;
+            }
+            let name: String = chars[start..*pos].iter().collect();
+            if let Some(value) = consts.get(&name) {
+                return Ok(*value);
+            }
+            match labels {
+                // the expression evaluator must report an undefined label as an ordinary
+                // `AsmError`, not panic, regardless of whether `.org` support is present
+                Some(labels) => match labels.get(&name) {
+                    Some(addr) => Ok(*addr as i64),
+                    None => Err(Error::LabelUndefined(name)),
+                },
+                None => Err(Error::ConstUndefined),
+            }
+        }
+        _ => Err(Error::ArgParse),
+    }
+}
+
+/// Range-checks an evaluated expression into the 16-bit operand field, honoring two's complement
+fn operand_from_i64(value: i64) -> Result<u16, Error> {
+    if (0..=u16::MAX as i64).contains(&value) {
+        Ok(value as u16)
+    } else if (i16::MIN as i64..0).contains(&value) {
+        Ok(value as i16 as u16)
+    } else {
+        Err(Error::OperandOverflow)
+    }
+}
+
+/// Range-checks an `.org` target into a 16-bit address; unlike an instruction operand this is
+/// unsigned, so a negative value is rejected outright rather than two's-complement wrapped
+fn org_address_from_i64(value: i64) -> Result<u16, Error> {
+    if (0..=u16::MAX as i64).contains(&value) {
+        Ok(value as u16)
+    } else {
+        Err(Error::OperandOverflow)
     }
-    parse(tokens, opcodes, labels)
 }
 
-fn lex(input: String) -> (Vec<Vec<Token>>, HashMap<String, u16>) {
+#[allow(clippy::type_complexity)]
+fn lex(
+    input: String,
+) -> Result<
+    (
+        Vec<Vec<Token>>,
+        HashMap<String, MacroDef>,
+        HashMap<String, i64>,
+    ),
+    AsmError,
+> {
     let lines: Vec<_> = input.trim().split("\n").map(|x| x.trim()).collect();
     let mut tokens: Vec<Vec<Token>> = Vec::new();
-    let mut labels: HashMap<String, u16> = HashMap::new();
+    let mut macros: HashMap<String, MacroDef> = HashMap::new();
+    let mut consts: HashMap<String, i64> = HashMap::new();
 
-    'line_loop: for (i, line) in lines.into_iter().enumerate() {
-        let mut line_tokens: Vec<Token> = Vec::new();
+    let mut i = 0;
+    'line_loop: while i < lines.len() {
+        let line_num = i as u32;
+        let line = lines[i];
+        i += 1;
         if line.len() == 0 {
             continue;
         }
-        let symbols: Vec<Vec<char>> = line
-            .split(" ")
-            .map(|x| x.trim().chars().collect())
-            .collect();
+        let symbols = merge_parens(scan_symbols(line));
+
+        // `const NAME = VALUE` or `.equ NAME VALUE` named constant directive
+        let keyword: String = symbols[0].1.iter().collect();
+        if keyword == "const" || keyword == ".equ" {
+            if symbols.len() < 2 {
+                let keyword_token =
+                    Token::new(TokenType::Opcode, keyword.clone(), line_num, symbols[0].0);
+                return Err(AsmError::new(Error::ArgParse, keyword_token));
+            }
+            let name: String = symbols[1].1.iter().collect();
+            let value_symbols: Vec<_> = symbols[2..]
+                .iter()
+                .filter(|(_, symbol)| symbol.iter().collect::<String>() != "=")
+                .collect();
+            let expr: String = value_symbols
+                .iter()
+                .map(|(_, symbol)| symbol.iter().collect::<String>())
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            let name_token = Token::new(TokenType::Opcode, name.clone(), line_num, symbols[1].0);
+            let value =
+                eval_expr(&expr, &consts, None).map_err(|err| AsmError::new(err, name_token))?;
+            consts.insert(name, value);
+            continue;
+        }
+
+        // `.org ADDR` directive: sets the address counter for subsequent instructions/labels.
+        // Kept as a marker line (like a label declaration) so it survives macro expansion and
+        // is resolved once instruction addresses are assigned.
+        if keyword == ".org" {
+            let expr: String = symbols[1..]
+                .iter()
+                .map(|(_, symbol)| symbol.iter().collect::<String>())
+                .collect::<Vec<_>>()
+                .join(" ");
+            tokens.push(vec![Token::new(
+                TokenType::Org,
+                expr,
+                line_num,
+                symbols[0].0,
+            )]);
+            continue;
+        }
 
-        'tokenize_line: for symbol in symbols {
+        // `.macro name params... \n body... \n .endm` definition
+        if keyword == ".macro" {
+            if symbols.len() < 2 {
+                let keyword_token =
+                    Token::new(TokenType::Opcode, keyword.clone(), line_num, symbols[0].0);
+                return Err(AsmError::new(Error::ArgParse, keyword_token));
+            }
+            let name: String = symbols[1].1.iter().collect();
+            let params: Vec<String> = symbols[2..]
+                .iter()
+                .map(|(_, symbol)| classify_symbol(symbol).1)
+                .collect();
+
+            let mut body: Vec<Vec<Token>> = Vec::new();
+            while i < lines.len() && lines[i].trim() != ".endm" {
+                let body_line_num = i as u32;
+                let body_line = lines[i];
+                i += 1;
+                if body_line.len() == 0 {
+                    continue;
+                }
+
+                let mut line_tokens: Vec<Token> = Vec::new();
+                'tokenize_body: for (chars, symbol) in merge_parens(scan_symbols(body_line)) {
+                    if symbol[0] == ';' {
+                        break 'tokenize_body; // comment
+                    }
+                    let (token_type, content) = classify_symbol(&symbol);
+                    line_tokens.push(Token::new(token_type, content, body_line_num, chars));
+                }
+                if line_tokens.len() > 0 {
+                    body.push(line_tokens);
+                }
+            }
+            i += 1; // consume the `.endm` line
+
+            macros.insert(name, MacroDef { params, body });
+            continue;
+        }
+
+        let mut line_tokens: Vec<Token> = Vec::new();
+        'tokenize_line: for (chars, symbol) in symbols {
             let (token_type, content) = match symbol[0] {
                 ';' => break 'tokenize_line, // comment
                 'r' => (TokenType::Reg, symbol[1..].iter().collect()),
@@ -274,18 +899,21 @@ fn lex(input: String) -> (Vec<Vec<Token>>, HashMap<String, u16>) {
                 '\'' => (TokenType::Label, symbol[1..].iter().collect()),
                 _ => {
                     if symbol[symbol.len() - 1] == ':' {
-                        // Token::new(TokenType::Label, symbol.iter().collect())
-                        labels.insert(
+                        // label declaration: keep its place in the stream as a marker line so
+                        // its address can be resolved after macro expansion settles line counts
+                        tokens.push(vec![Token::new(
+                            TokenType::Label,
                             symbol[..symbol.len() - 1].iter().collect(),
-                            tokens.len() as u16,
-                        );
+                            line_num,
+                            chars,
+                        )]);
                         continue 'line_loop;
                     } else {
                         (TokenType::Opcode, symbol.iter().collect())
                     }
                 }
             };
-            line_tokens.push(Token::new(token_type, content, i as u32, (0, 0)));
+            line_tokens.push(Token::new(token_type, content, line_num, chars));
         }
 
         if line_tokens.len() > 0 {
@@ -293,7 +921,88 @@ fn lex(input: String) -> (Vec<Vec<Token>>, HashMap<String, u16>) {
         }
     }
 
-    (tokens, labels)
+    Ok((tokens, macros, consts))
+}
+
+/// Expands macro invocations in place, recursing into macros that call other macros
+fn expand_line(
+    line: Vec<Token>,
+    macros: &HashMap<String, MacroDef>,
+    depth: u32,
+) -> Result<Vec<Vec<Token>>, AsmError> {
+    if depth > MAX_MACRO_DEPTH {
+        return Err(AsmError::new(Error::MacroRecursion, line[0].clone()));
+    }
+
+    if let TokenType::Opcode = line[0].token {
+        if let Some(macro_def) = macros.get(&line[0].content) {
+            if macro_def.params.len() != line.len() - 1 {
+                return Err(AsmError::new(Error::ArgsNumber, line[0].clone()));
+            }
+            let args = &line[1..];
+
+            let mut expanded = Vec::new();
+            for body_line in &macro_def.body {
+                let substituted: Vec<Token> = body_line
+                    .iter()
+                    .map(|token| {
+                        match macro_def
+                            .params
+                            .iter()
+                            .position(|param| param == &token.content)
+                        {
+                            Some(index) => args[index].clone(),
+                            None => token.clone(),
+                        }
+                    })
+                    .collect();
+                expanded.extend(expand_line(substituted, macros, depth + 1)?);
+            }
+            return Ok(expanded);
+        }
+    }
+
+    Ok(vec![line])
+}
+
+/// Pre-expansion pass: replaces every macro invocation with its (recursively expanded) body
+fn expand_macros(
+    tokens: Vec<Vec<Token>>,
+    macros: &HashMap<String, MacroDef>,
+) -> Result<Vec<Vec<Token>>, AsmError> {
+    let mut expanded = Vec::new();
+    for line in tokens {
+        expanded.extend(expand_line(line, macros, 0)?);
+    }
+    Ok(expanded)
+}
+
+/// First pass: walks the (macro-expanded) token stream maintaining a running address counter,
+/// honoring `.org ADDR` directives that relocate it, and records each label's address at the
+/// point it was declared. Strips the `.org`/label marker lines, pairing every remaining
+/// instruction line with the address it was assigned.
+fn assign_addresses(
+    tokens: Vec<Vec<Token>>,
+    consts: &HashMap<String, i64>,
+) -> Result<(HashMap<String, u16>, Vec<(u16, Vec<Token>)>), AsmError> {
+    let mut labels: HashMap<String, u16> = HashMap::new();
+    let mut instructions: Vec<(u16, Vec<Token>)> = Vec::new();
+    let mut address: u16 = 0;
+
+    for line in tokens {
+        if line.len() == 1 && line[0].token == TokenType::Label {
+            labels.insert(line[0].content.clone(), address);
+        } else if line.len() == 1 && line[0].token == TokenType::Org {
+            address = eval_expr(&line[0].content, consts, None)
+                .and_then(org_address_from_i64)
+                .map_err(|err| AsmError::new(err, line[0].clone()))?;
+        } else {
+            instructions.push((address, line));
+            address = address.wrapping_add(1);
+        }
+    }
+
+    Ok((labels, instructions))
 }
 
 fn parse_addr_mode_operand(
@@ -329,18 +1038,20 @@ fn parse_addr_mode_operand(
             } else {
                 return Err(Error::InhInvalid);
             }
-        } // _ => return Err(AsmError::new(Error::OperandExpected, i)),
+        }
+        TokenType::Org => return Err(Error::OperandExpected),
     })
 }
 
 fn parse(
-    tokens: Vec<Vec<Token>>,
+    tokens: Vec<(u16, Vec<Token>)>,
     opcodes: HashMap<String, Opcode>,
     labels: HashMap<String, u16>,
-) -> Result<Vec<Inst>, AsmError> {
-    let mut instructions: Vec<Inst> = Vec::new();
+    consts: HashMap<String, i64>,
+) -> Result<Vec<(u16, Inst)>, AsmError> {
+    let mut instructions: Vec<(u16, Inst)> = Vec::new();
 
-    for (i, line) in tokens.iter().enumerate() {
+    for (i, (address, line)) in tokens.iter().enumerate() {
         let opcode = if let TokenType::Opcode = &line[0].token {
             if let Some(value) = opcodes.get(&line[0].content) {
                 value
@@ -362,18 +1073,16 @@ fn parse(
             Err(err) => return Err(AsmError::new(err, operand.clone())),
             Ok(addr_mode) => addr_mode,
         };
-        // operand parse
+        // operand parse: the operand is a constant expression over literals, named constants
+        // defined via `const`/`.equ`, and labels
         let mut operand = match addr_mode {
             AddrMode::Inh => 0,
             _ => {
-                if operand.token == TokenType::Label {
-                    *labels.get(&operand.content).unwrap()
-                } else {
-                    if let Ok(value) = operand.content.parse() {
-                        value
-                    } else {
-                        return Err(AsmError::new(Error::ArgParse, operand.clone()));
-                    }
+                let value =
+                    eval_expr(&operand.content, &consts, Some(&labels)).and_then(operand_from_i64);
+                match value {
+                    Ok(value) => value,
+                    Err(err) => return Err(AsmError::new(err, operand.clone())),
                 }
             }
         };
@@ -398,13 +1107,153 @@ fn parse(
             }
         }
 
-        instructions.push(Inst {
-            addr_mode: addr_mode as u8,
-            opcode: opcode.opcode,
-            reg_z: reg_args[0],
-            reg_x: reg_args[1],
-            operand,
-        });
+        instructions.push((
+            *address,
+            Inst {
+                addr_mode: addr_mode as u8,
+                opcode: opcode.opcode,
+                reg_z: reg_args[0],
+                reg_x: reg_args[1],
+                operand,
+            },
+        ));
     }
     Ok(instructions)
 }
+
+#[cfg(test)]
+mod macro_tests {
+    use super::*;
+
+    fn token(token: TokenType, content: &str) -> Token {
+        Token::new(token, content.to_string(), 0, (0, 0))
+    }
+
+    #[test]
+    fn expands_macro_body_substituting_params() {
+        let mut macros = HashMap::new();
+        macros.insert(
+            "push".to_string(),
+            MacroDef {
+                params: vec!["x".to_string()],
+                body: vec![
+                    vec![token(TokenType::Opcode, "sto"), token(TokenType::Reg, "x")],
+                    vec![token(TokenType::Opcode, "dec")],
+                ],
+            },
+        );
+
+        let call = vec![token(TokenType::Opcode, "push"), token(TokenType::Reg, "3")];
+        let expanded = expand_line(call, &macros, 0).unwrap();
+
+        assert_eq!(expanded.len(), 2);
+        assert_eq!(expanded[0][1].content, "3");
+        assert_eq!(expanded[1][0].content, "dec");
+    }
+
+    #[test]
+    fn leaves_non_macro_opcodes_untouched() {
+        let macros = HashMap::new();
+        let call = vec![token(TokenType::Opcode, "add"), token(TokenType::Reg, "1")];
+        let expanded = expand_line(call.clone(), &macros, 0).unwrap();
+        assert_eq!(expanded.len(), 1);
+        assert_eq!(expanded[0][0].content, call[0].content);
+    }
+
+    #[test]
+    fn rejects_argument_count_mismatch() {
+        let mut macros = HashMap::new();
+        macros.insert(
+            "push".to_string(),
+            MacroDef {
+                params: vec!["x".to_string()],
+                body: vec![vec![
+                    token(TokenType::Opcode, "sto"),
+                    token(TokenType::Reg, "x"),
+                ]],
+            },
+        );
+
+        let call = vec![token(TokenType::Opcode, "push")];
+        let err = expand_line(call, &macros, 0).unwrap_err();
+        assert!(matches!(err.err, Error::ArgsNumber));
+    }
+
+    #[test]
+    fn rejects_recursion_past_depth_limit() {
+        let mut macros = HashMap::new();
+        macros.insert(
+            "loop".to_string(),
+            MacroDef {
+                params: vec![],
+                body: vec![vec![token(TokenType::Opcode, "loop")]],
+            },
+        );
+
+        let call = vec![token(TokenType::Opcode, "loop")];
+        let err = expand_line(call, &macros, 0).unwrap_err();
+        assert!(matches!(err.err, Error::MacroRecursion));
+    }
+}
+
+#[cfg(test)]
+mod eval_expr_tests {
+    use super::*;
+
+    #[test]
+    fn evaluates_arithmetic_with_precedence_and_parens() {
+        let consts = HashMap::new();
+        assert_eq!(eval_expr("2 + 3 * 4", &consts, None).unwrap(), 14);
+        assert_eq!(eval_expr("(2 + 3) * 4", &consts, None).unwrap(), 20);
+        assert_eq!(eval_expr("-5 + 2", &consts, None).unwrap(), -3);
+    }
+
+    #[test]
+    fn resolves_hex_literals_and_named_constants() {
+        let mut consts = HashMap::new();
+        consts.insert("base".to_string(), 0x10);
+        assert_eq!(eval_expr("0x10 + base", &consts, None).unwrap(), 0x20);
+    }
+
+    #[test]
+    fn resolves_labels_when_provided_and_errors_when_not() {
+        let consts = HashMap::new();
+        let mut labels = HashMap::new();
+        labels.insert("table".to_string(), 7u16);
+
+        assert_eq!(eval_expr("table + 4", &consts, Some(&labels)).unwrap(), 11);
+
+        match eval_expr("table", &consts, None) {
+            Err(Error::ConstUndefined) => {}
+            other => panic!("expected ConstUndefined, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn errors_on_undefined_label_instead_of_panicking() {
+        let consts = HashMap::new();
+        let labels: HashMap<String, u16> = HashMap::new();
+
+        match eval_expr("missing", &consts, Some(&labels)) {
+            Err(Error::LabelUndefined(name)) => assert_eq!(name, "missing"),
+            other => panic!("expected LabelUndefined, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn errors_on_division_by_zero() {
+        let consts = HashMap::new();
+        assert!(matches!(
+            eval_expr("1 / 0", &consts, None),
+            Err(Error::ArgParse)
+        ));
+    }
+
+    #[test]
+    fn range_checks_operands_and_org_addresses_differently() {
+        assert!(operand_from_i64(100_000).is_err());
+        assert_eq!(operand_from_i64(-1).unwrap(), 0xffff);
+        assert!(org_address_from_i64(-1).is_err());
+        assert_eq!(org_address_from_i64(0x100).unwrap(), 0x100);
+    }
+}